@@ -1,3 +1,5 @@
+use std::collections::HashSet;
+
 use macroquad::color;
 use macroquad::prelude::*;
 
@@ -30,18 +32,28 @@ impl Particle {
 
 
 #[derive(Default)]
-struct GridCell<'a> {
+struct GridCell {
     /// upper left, right, bottom left, right
-    children: Option<[Box<GridCell<'a>>; 4]>,
+    children: Option<[Box<GridCell>; 4]>,
 
-    particles: Vec<&'a Particle>,
+    particles: Vec<usize>,
 
     pos1: Vec2, // upper left corner
     pos2: Vec2, // bottom right corner
 }
 
-impl<'a> GridCell<'a> {
-    fn new(pos1: Vec2, pos2: Vec2, part: usize, particles: Vec<&'a Particle>) -> Self {
+impl GridCell {
+    // Root cell: covers pos1..pos2 with no quadrant split, then subdivides.
+    fn root(pos1: Vec2, pos2: Vec2, particles: &[Particle]) -> Self {
+        let mut cell = GridCell::default();
+        cell.pos1 = pos1;
+        cell.pos2 = pos2;
+        cell.particles = Self::filter(pos1, pos2, particles, (0..particles.len()).collect());
+        cell.subdivide(particles);
+        cell
+    }
+
+    fn new(pos1: Vec2, pos2: Vec2, part: usize, particles: &[Particle], indices: Vec<usize>) -> Self {
         let mut cell = GridCell::default();
 
         let mut p1 = pos1;
@@ -62,29 +74,43 @@ impl<'a> GridCell<'a> {
 
         cell.pos1 = p1;
         cell.pos2 = p2;
+        cell.particles = Self::filter(p1, p2, particles, indices);
+        cell.subdivide(particles);
 
-        for particle in particles {
-            if particle.pos.x > p1.x && particle.pos.x < p2.x &&
-                particle.pos.y > p1.y && particle.pos.y < p2.y {
-                cell.particles.push(particle);
-            }
-        }
+        cell
+    }
 
-        if cell.particles.len() > 6 {
-            let mut child_cells: [Box<GridCell>; 4] = Default::default();
+    fn filter(p1: Vec2, p2: Vec2, particles: &[Particle], indices: Vec<usize>) -> Vec<usize> {
+        indices
+            .into_iter()
+            .filter(|&i| {
+                let pos = particles[i].pos;
+                pos.x > p1.x && pos.x < p2.x && pos.y > p1.y && pos.y < p2.y
+            })
+            .collect()
+    }
 
-            for i in 0..4 {
-                child_cells[i] = Box::new(GridCell::new(cell.pos1, cell.pos2, i+1, Vec::new()));
+    fn subdivide(&mut self, particles: &[Particle]) {
+        if self.particles.len() <= 6 { return; }
 
-            }
-            cell.children = Some(child_cells);
-        }
+        // Stop once the *children* would drop below a particle's diameter, so
+        // that get_neighbors' 1-ring search is still guaranteed to reach
+        // anything that could collide with this cell.
+        let size = self.pos2 - self.pos1;
+        let max_radius = self.particles.iter().map(|&i| particles[i].radius).fold(0.0f32, f32::max);
+        if size.x / 2.0 < max_radius * 2.0 || size.y / 2.0 < max_radius * 2.0 { return; }
 
-        cell
+        let mut child_cells: [Box<GridCell>; 4] = Default::default();
+
+        for i in 0..4 {
+            child_cells[i] = Box::new(GridCell::new(self.pos1, self.pos2, i+1, particles, self.particles.clone()));
+
+        }
+        self.children = Some(child_cells);
     }
 }
 
-impl<'a> GridCell<'a> {
+impl GridCell {
     fn draw(&self) {
         if let Some(children) = self.children.as_ref() {
             for child in children.iter() {
@@ -98,16 +124,16 @@ impl<'a> GridCell<'a> {
 
 
 #[derive(Default)]
-struct State<'a> {
+struct State {
     particles: Vec<Particle>,
-    grid: GridCell<'a>,
+    grid: GridCell,
     gravity: Vec2,
     friction: f32,
     last_iter_count: usize,
 }
 
 
-impl<'a> State<'a> {
+impl State {
     fn draw(&self) {
         for particle in &self.particles {
             particle.draw();
@@ -193,77 +219,94 @@ impl<'a> State<'a> {
                         }
                     }
                 }
+            }
+
+            // Rebuild the quadtree every substep: positions move each
+            // substep, so a snapshot from before this frame's substeps would
+            // go stale and miss collisions.
+            self.grid = GridCell::root(Vec2::new(0.0, 0.0), Vec2::new(screen_width, screen_height), &self.particles);
 
-                if i == self.particles.len() - 1 { continue; }
+            // Broad-phase collisions: only test particles that share a leaf
+            // cell (or a neighboring one) instead of every pair in the sim.
+            self.resolve_collisions();
+        }
+    }
+
+    fn resolve_collisions(&mut self) {
+        for (i, j) in self.collision_pairs() {
+            self.collide(i, j);
+        }
+    }
 
+    // Each leaf cell checked against itself plus its neighbors; pairs are
+    // deduplicated since a pair straddling two neighboring cells would
+    // otherwise be reachable from both sides.
+    fn collision_pairs(&self) -> Vec<(usize, usize)> {
+        let leaf_cells = get_leaf_cells(&self.grid);
+        let mut checked: HashSet<(usize, usize)> = HashSet::new();
+        let mut pairs = Vec::new();
 
-                // Now check against other particles
-                for j in i+1..self.particles.len() {
-                    let (left, right) = self.particles.split_at_mut(j);
-                    let p1 = &mut left[i];
-                    let p2 = &mut right[0];
+        for leaf in &leaf_cells {
+            let mut group = leaf.particles.clone();
+            for neighbor in get_neighbors(&leaf_cells, leaf) {
+                group.extend_from_slice(&neighbor.particles);
+            }
 
-                    let dist = (p1.pos - p2.pos).length();
+            for a in 0..group.len() {
+                for b in a+1..group.len() {
+                    let (i, j) = if group[a] < group[b] { (group[a], group[b]) } else { (group[b], group[a]) };
 
-                    // Continue if we dont collide
-                    let overlap = p1.radius + p2.radius - dist;
+                    if i == j || !checked.insert((i, j)) { continue; }
 
-                    if overlap < 0.0 { continue; }
-                    if dist < 0.01 { continue; }
+                    pairs.push((i, j));
+                }
+            }
+        }
 
-                    let rel_vel = (p2.vel - p1.vel) * 0.5;
+        pairs
+    }
 
-                    let delta_pos = p2.pos - p1.pos;
-                    let normal = delta_pos.normalize();
+    // i must be < j.
+    fn collide(&mut self, i: usize, j: usize) {
+        let (left, right) = self.particles.split_at_mut(j);
+        let p1 = &mut left[i];
+        let p2 = &mut right[0];
 
-                    // Push them out of each other
-                    p1.pos -= normal * overlap * 0.5;
-                    p2.pos += normal * overlap * 0.5;
+        let dist = (p1.pos - p2.pos).length();
 
-                    let vel_along_normal = normal * normal.dot(rel_vel);
-                    let vel_along_tangent = rel_vel - vel_along_normal;
+        // Return early if we dont collide
+        let overlap = p1.radius + p2.radius - dist;
 
-                    p1.vel += rel_vel;
-                    p2.vel -= rel_vel;
+        if overlap < 0.0 { return; }
+        if dist < 0.01 { return; }
 
-                    let elasticity = 0.8;
-                    p1.vel += vel_along_normal * elasticity;
-                    p2.vel -= vel_along_normal * elasticity;
+        let rel_vel = (p2.vel - p1.vel) * 0.5;
 
-                    p1.vel -= vel_along_tangent * (1.0 - self.friction);
-                    p2.vel += vel_along_tangent * (1.0 - self.friction);
-                }
-            }
-        }
-    }
+        let delta_pos = p2.pos - p1.pos;
+        let normal = delta_pos.normalize();
 
-    fn get_neighbors(&'a self, cell: &GridCell<'a>) -> Vec<&'a GridCell<'a>>
-    {
-        let mut neighbors: Vec<&GridCell> = Vec::default();
-        let leaf_cells = get_leaf_cells(&self.grid);
+        // Push them out of each other
+        p1.pos -= normal * overlap * 0.5;
+        p2.pos += normal * overlap * 0.5;
 
-        let p1 = cell.pos1;
-        let p2 = cell.pos2;
+        let vel_along_normal = normal * normal.dot(rel_vel);
+        let vel_along_tangent = rel_vel - vel_along_normal;
 
-        for leaf in leaf_cells {
-            if leaf.pos1 == p1 && leaf.pos2 == p2 { continue; }
+        p1.vel += rel_vel;
+        p2.vel -= rel_vel;
 
-            if leaf.pos1.x <= p2.x &&
-                leaf.pos2.x >= p1.x &&
-                leaf.pos1.y <= p2.y &&
-                leaf.pos2.y >= p1.y {
-                neighbors.push(leaf);
-            }
-        }
+        let elasticity = 0.8;
+        p1.vel += vel_along_normal * elasticity;
+        p2.vel -= vel_along_normal * elasticity;
 
-        neighbors
+        p1.vel -= vel_along_tangent * (1.0 - self.friction);
+        p2.vel += vel_along_tangent * (1.0 - self.friction);
     }
 
-
 }
 
 
-fn get_leaf_cells<'a>(cell: &'a GridCell) -> Vec<&'a GridCell<'a>> {
+fn get_leaf_cells<'a>(cell: &'a GridCell) -> Vec<&'a GridCell> {
     let mut cells: Vec<&GridCell> = Vec::new();
 
     if cell.children.is_some() {
@@ -277,6 +320,26 @@ fn get_leaf_cells<'a>(cell: &'a GridCell) -> Vec<&'a GridCell<'a>> {
     cells
 }
 
+fn get_neighbors<'a>(leaf_cells: &[&'a GridCell], cell: &GridCell) -> Vec<&'a GridCell> {
+    let mut neighbors: Vec<&GridCell> = Vec::default();
+
+    let p1 = cell.pos1;
+    let p2 = cell.pos2;
+
+    for &leaf in leaf_cells {
+        if leaf.pos1 == p1 && leaf.pos2 == p2 { continue; }
+
+        if leaf.pos1.x <= p2.x &&
+            leaf.pos2.x >= p1.x &&
+            leaf.pos1.y <= p2.y &&
+            leaf.pos2.y >= p1.y {
+            neighbors.push(leaf);
+        }
+    }
+
+    neighbors
+}
+
 #[macroquad::main("Hello World!")]
 async fn main() {
     println!("Hello, world!");